@@ -0,0 +1,297 @@
+//! # Audio output driver
+//!
+//! Drives the board's stereo line/headphone output: a WM8994-class codec
+//! configured over I2C1, fed an I2S stream by the SAI2 block, which in turn
+//! pulls PCM samples out of a double-buffered DMA ring in SRAM. The OS
+//! fills whichever half of the ring isn't currently being played out, and
+//! the DMA half/full-transfer interrupts tell us (and so it) when to swap.
+
+use crate::hal::{device, prelude::*, rcc::Clocks};
+use neotron_common_bios as common;
+
+/// The WM8994's I2C address (`CS` pin tied low, as on the DISCOVERY board).
+const CODEC_I2C_ADDR: u8 = 0x1A;
+
+/// Registers we actually touch - just enough to get a DAC path enabled at
+/// a fixed sample rate and volume. This is nowhere near the whole register
+/// map; the WM8994 datasheet has hundreds of these.
+mod reg {
+	pub const SOFTWARE_RESET: u16 = 0x0000;
+	pub const POWER_MANAGEMENT_1: u16 = 0x0001;
+	pub const POWER_MANAGEMENT_5: u16 = 0x0005;
+	pub const AIF1_CONTROL_1: u16 = 0x0300;
+	pub const AIF1_RATE: u16 = 0x0210;
+	pub const DAC1_LEFT_VOLUME: u16 = 0x0610;
+	pub const DAC1_RIGHT_VOLUME: u16 = 0x0611;
+	pub const DC_SERVO_1: u16 = 0x0054;
+}
+
+/// Samples per second our one supported output format runs at.
+const SAMPLE_RATE_HZ: u32 = 48_000;
+
+/// Number of stereo channels we mix down to.
+const CHANNELS: u8 = 2;
+
+/// Number of 16-bit samples in each half of the DMA ring. At 48kHz stereo
+/// this is a little over 10ms per half, which is plenty of slack for the OS
+/// to keep up without the DMA ever lapping it.
+const HALF_LEN: usize = 512 * CHANNELS as usize;
+
+/// The DMA ring buffer the SAI reads from. `'static` because the DMA
+/// controller needs a fixed address to stream from for the life of the
+/// peripheral.
+static mut DMA_RING: [u16; HALF_LEN * 2] = [0; HALF_LEN * 2];
+
+/// Which half of `DMA_RING` the DMA controller is currently reading. The
+/// other half (`ACTIVE_HALF ^ 1`) is the one it's safe for the OS to write
+/// fresh samples into. Updated from the DMA half/full-transfer interrupt.
+static ACTIVE_HALF: core::sync::atomic::AtomicU8 = core::sync::atomic::AtomicU8::new(0);
+
+/// Owns the codec's I2C bus, the SAI block, and the DMA stream feeding it.
+pub struct AudioOutput {
+	i2c: device::I2C1,
+	sai: device::SAI2,
+	dma: device::DMA2,
+	volume: u8,
+	/// How many bytes of the currently-writable half have already been
+	/// filled this period. Reset to zero whenever `handle_dma_interrupt`
+	/// hands the OS a fresh half to write into.
+	write_cursor: usize,
+}
+
+// The peripherals here are only ever touched with the `GLOBAL_BOARD` lock
+// held; the DMA ring is `'static` and only one half is ever writable at a
+// time by contract with `ACTIVE_HALF`.
+unsafe impl Send for AudioOutput {}
+
+impl AudioOutput {
+	/// Bring up I2C1 (codec control), SAI2 (the I2S bitstream) and DMA2
+	/// (feeding it from `DMA_RING`), and get the codec into a DAC-enabled,
+	/// known-volume state.
+	pub fn init(i2c: device::I2C1, sai: device::SAI2, dma: device::DMA2, clocks: &Clocks) -> AudioOutput {
+		configure_pins();
+		unsafe {
+			let rcc = &*device::RCC::ptr();
+			rcc.apb1enr.modify(|_, w| w.i2c1en().set_bit());
+			rcc.apb2enr.modify(|_, w| w.sai2en().set_bit());
+			rcc.ahb1enr.modify(|_, w| w.dma2en().set_bit());
+		}
+
+		// Standard-mode (100kHz) I2C, near enough for register pokes that
+		// happen at init and on the rare volume change.
+		let i2c_clk = clocks.pclk1().0;
+		i2c.timingr
+			.write(|w| unsafe { w.bits((i2c_clk / 100_000 / 4).max(1)) });
+		i2c.cr1.write(|w| w.pe().set_bit());
+
+		let mut codec = AudioOutput {
+			i2c,
+			sai,
+			dma,
+			volume: 255,
+			write_cursor: 0,
+		};
+		codec.init_codec();
+		codec.init_sai();
+		codec.init_dma();
+		codec
+	}
+
+	/// Walk the WM8994 through a reset and just enough power-up steps to
+	/// get a stereo DAC path running into the headphone/line output.
+	fn init_codec(&mut self) {
+		self.write_codec_reg(reg::SOFTWARE_RESET, 0x0000);
+		self.write_codec_reg(reg::POWER_MANAGEMENT_1, 0x0003); // VMID, bias up
+		self.write_codec_reg(reg::POWER_MANAGEMENT_5, 0x0303); // DAC1L/R enable
+		self.write_codec_reg(reg::AIF1_RATE, 0x0083); // 48kHz, MCLK/1
+		self.write_codec_reg(reg::AIF1_CONTROL_1, 0x4010); // I2S, 16-bit
+		self.write_codec_reg(reg::DC_SERVO_1, 0x000F);
+		self.set_volume_registers(self.volume);
+	}
+
+	/// Push the current volume level out to both DAC volume registers. The
+	/// WM8994 wants 0-255 mapped onto its 0x0-0xBF "digital gain" scale,
+	/// with the top bit set to latch both channels together.
+	fn set_volume_registers(&mut self, level: u8) {
+		let gain = ((level as u16) * 0xBF) / 255;
+		self.write_codec_reg(reg::DAC1_LEFT_VOLUME, 0x0100 | gain);
+		self.write_codec_reg(reg::DAC1_RIGHT_VOLUME, 0x0100 | gain);
+	}
+
+	/// Configure SAI2 block A as an I2S master transmitter: 16-bit stereo
+	/// frames at `SAMPLE_RATE_HZ`, clocked from the block's own MCLK
+	/// divider rather than sharing a clock with another SAI block.
+	fn init_sai(&mut self) {
+		self.sai.bcr1.write(|w| unsafe {
+			w.mode()
+				.bits(0b00) // master transmitter
+				.prot()
+				.bits(0b00) // free protocol, framed as I2S by the setup below
+				.ds()
+				.bits(0b100) // 16-bit data size
+				.ckstr()
+				.set_bit() // clock strobing edge for I2S
+				.mono()
+				.clear_bit() // stereo
+		});
+		self.sai.bfrcr.write(|w| unsafe {
+			w.frl().bits(31).sslen().clear_bit().fsoff().set_bit().fspol().clear_bit()
+		});
+		self.sai.bslotr.write(|w| unsafe { w.sloten().bits(0b11).nbslot().bits(1) });
+		self.sai.bcr1.modify(|_, w| w.saien().set_bit());
+	}
+
+	/// Point DMA2 stream 4 / channel 1 (SAI2A's DMA request on this part)
+	/// at `DMA_RING` in circular double-buffer mode, so the two halves
+	/// alternate forever without CPU intervention beyond refilling them.
+	fn init_dma(&mut self) {
+		let ring_addr = unsafe { DMA_RING.as_ptr() as u32 };
+		let stream = &self.dma.st[4];
+		stream.cr.write(|w| unsafe {
+			w.chsel()
+				.bits(1)
+				.dir()
+				.bits(0b01) // memory to peripheral
+				.msize()
+				.bits(0b01) // 16-bit memory words
+				.psize()
+				.bits(0b01) // 16-bit peripheral words
+				.minc()
+				.set_bit()
+				.circ()
+				.set_bit()
+				.htie()
+				.set_bit()
+				.tcie()
+				.set_bit()
+		});
+		stream.ndtr.write(|w| unsafe { w.ndt().bits((HALF_LEN * 2) as u16) });
+		stream.m0ar.write(|w| unsafe { w.bits(ring_addr) });
+		stream.cr.modify(|_, w| w.en().set_bit());
+	}
+
+	/// Acknowledge the half/full-transfer interrupt, flip which half of the
+	/// ring the OS should be writing into, and reset the write cursor - the
+	/// half it's about to start filling has never been written to this
+	/// period, whatever was in it last time around.
+	pub fn handle_dma_interrupt(&mut self) {
+		self.dma.hifcr.write(|w| unsafe { w.bits(0x3D) }); // clear stream 4 flags (bits 0, 2-5)
+		let half = ACTIVE_HALF.load(core::sync::atomic::Ordering::Acquire);
+		ACTIVE_HALF.store(half ^ 1, core::sync::atomic::Ordering::Release);
+		self.write_cursor = 0;
+	}
+
+	/// Write `reg` on the codec over I2C, MSB of the 16-bit value first,
+	/// same as every other WM8994 control write.
+	fn write_codec_reg(&mut self, reg: u16, value: u16) {
+		let payload = [(reg >> 8) as u8, reg as u8, (value >> 8) as u8, value as u8];
+		for &byte in &payload {
+			while self.i2c.isr.read().txis().bit_is_clear() {
+				cortex_m::asm::nop();
+			}
+			self.i2c.txdr.write(|w| unsafe { w.txdata().bits(byte) });
+		}
+	}
+
+	/// Static information about the one mixer channel we expose: the
+	/// master output level.
+	pub fn mixer_channel_info(&self, channel: u8) -> Option<common::audio::MixerChannelInfo> {
+		if channel != 0 {
+			return None;
+		}
+		Some(common::audio::MixerChannelInfo {
+			name: "Master".into(),
+			direction: common::audio::Direction::Output,
+			current_level: self.volume,
+		})
+	}
+
+	/// Set the master output level (0-255) and push it out to the codec.
+	pub fn set_mixer_level(&mut self, channel: u8, level: u8) -> common::Result<()> {
+		if channel != 0 {
+			return common::Result::Err(common::Error::InvalidDevice);
+		}
+		self.volume = level;
+		self.set_volume_registers(level);
+		common::Result::Ok(())
+	}
+
+	/// The fixed output configuration - we only support the one rate/width
+	/// combination the codec was brought up with.
+	pub fn output_config(&self) -> common::audio::Config {
+		common::audio::Config {
+			sample_rate_hz: SAMPLE_RATE_HZ,
+			channels: CHANNELS,
+			bits_per_sample: 16,
+		}
+	}
+
+	/// How many bytes of the currently-writable half are still free - the
+	/// half's full size minus whatever `push_samples` has already written
+	/// into it since the last DMA swap.
+	pub fn space_available(&self) -> usize {
+		(HALF_LEN * core::mem::size_of::<u16>()).saturating_sub(self.write_cursor)
+	}
+
+	/// Copy one block of 16-bit PCM samples into whichever half of the ring
+	/// the DMA isn't currently reading from, appending after whatever this
+	/// period's earlier calls have already written rather than overwriting
+	/// them. `data` is truncated to whatever's left of the half's capacity
+	/// if it's larger.
+	pub fn push_samples(&mut self, data: &[u8]) -> usize {
+		let half = ACTIVE_HALF.load(core::sync::atomic::Ordering::Acquire) ^ 1;
+		let dest = unsafe { &mut DMA_RING[half as usize * HALF_LEN..(half as usize + 1) * HALF_LEN] };
+		let dest_bytes = dest.len() * 2;
+		let space = dest_bytes.saturating_sub(self.write_cursor);
+		let n = data.len().min(space) & !1;
+		let sample_offset = self.write_cursor / 2;
+		for (chunk, sample) in data[..n].chunks_exact(2).zip(dest[sample_offset..].iter_mut()) {
+			*sample = u16::from_le_bytes([chunk[0], chunk[1]]);
+		}
+		self.write_cursor += n;
+		n
+	}
+}
+
+/// Drive PB6 (SCL) / PB9 (SDA) into I2C1's alternate function, and the SAI2
+/// block A pins (FS/SCK/SD on PD12-PD13/PE5-PE6 on this board) into theirs.
+fn configure_pins() {
+	unsafe {
+		let rcc = &*device::RCC::ptr();
+		rcc.ahb1enr
+			.modify(|_, w| w.gpioben().set_bit().gpioden().set_bit().gpioeen().set_bit());
+
+		let gpiob = &*device::GPIOB::ptr();
+		for pin in [6u8, 9] {
+			gpiob
+				.moder
+				.modify(|r, w| w.bits((r.bits() & !(0b11 << (pin * 2))) | (0b10 << (pin * 2))));
+			gpiob
+				.otyper
+				.modify(|r, w| w.bits(r.bits() | (1 << pin))); // open-drain for I2C
+			let shift = (pin as usize % 8) * 4;
+			if pin < 8 {
+				gpiob.afrl.modify(|r, w| w.bits((r.bits() & !(0xF << shift)) | (4 << shift)));
+			} else {
+				gpiob.afrh.modify(|r, w| w.bits((r.bits() & !(0xF << shift)) | (4 << shift)));
+			}
+		}
+
+		let gpiod = &*device::GPIOD::ptr();
+		for pin in [12u8, 13] {
+			gpiod
+				.moder
+				.modify(|r, w| w.bits((r.bits() & !(0b11 << (pin * 2))) | (0b10 << (pin * 2))));
+			let shift = (pin as usize - 8) * 4;
+			gpiod.afrh.modify(|r, w| w.bits((r.bits() & !(0xF << shift)) | (10 << shift)));
+		}
+
+		let gpioe = &*device::GPIOE::ptr();
+		for pin in [5u8, 6] {
+			gpioe
+				.moder
+				.modify(|r, w| w.bits((r.bits() & !(0b11 << (pin * 2))) | (0b10 << (pin * 2))));
+			gpioe.afrl.modify(|r, w| w.bits((r.bits() & !(0xF << (pin * 4))) | (10 << (pin * 4))));
+		}
+	}
+}
@@ -52,19 +52,26 @@
 // Sub-Modules
 // ===========================================================================
 
-// None
+mod audio;
+mod block;
+mod ps2;
+mod usb;
+mod video;
 
 // ===========================================================================
 // Imports
 // ===========================================================================
 
+use core::cell::RefCell;
 use core::fmt::Write;
 use core::panic::PanicInfo;
 use core::sync::atomic::{self, Ordering};
-use cortex_m_rt::entry;
+use cortex_m_rt::{entry, exception};
 use hal::{
 	device,
+	interrupt,
 	prelude::*,
+	rtc::Rtc,
 	serial::{self, Serial},
 };
 use nb::block;
@@ -80,23 +87,116 @@ type AF7 = hal::gpio::Alternate<hal::gpio::AF7>;
 /// This holds our system state - all our HAL drivers, etc.
 #[allow(dead_code)]
 pub struct BoardInner {
-	/// USB Virtual COM-Port. Connect the USB mini-B connector to your PC to view.
-	usb_uart: hal::serial::Serial<
+	/// Plain UART on the PA9 (TX) / PB7 (RX) header pins. Despite the name
+	/// on the silkscreen this is *not* the USB port - see `usb_serial` for
+	/// that - but it's handy for bring-up with a TTL adapter and is what we
+	/// use to print the boot banner.
+	debug_uart: hal::serial::Serial<
 		device::USART1,
 		(hal::gpio::gpioa::PA9<AF7>, hal::gpio::gpiob::PB7<AF7>),
 	>,
+	/// The USB mini-B connector, presented to the host as a CDC-ACM virtual
+	/// COM port. This is Serial Device 0 - the one the BIOS docs mean by
+	/// "USB Serial interface".
+	usb_serial: usb::UsbSerial,
+	/// The STM32F7 on-chip RTC, running in the backup domain. We use it only
+	/// for second-resolution timekeeping that survives a reset - all the
+	/// sub-second work happens with `FRAMES_SINCE_SECOND`.
+	rtc: Rtc,
+	/// The LTDC controller and the SDRAM framebuffer it scans out of.
+	video: video::VideoController,
+	/// The SD-card connector, driven over SDMMC1.
+	sdcard: block::SdCard,
+	/// Bytes received on the header UART, pushed by the `USART1` RX
+	/// interrupt and drained by `serial_read`.
+	debug_uart_rx: RxRing,
+	/// The keyboard/mouse interface UART, decoded into HID events.
+	keyboard: ps2::Ps2Keyboard,
+	/// The WM8994 codec and the SAI/DMA path feeding it PCM audio.
+	audio: audio::AudioOutput,
+}
+
+/// Size of each of our byte ring buffers' storage, in bytes. One slot always
+/// stays empty, so a ring holds at most `RX_RING_LEN - 1` bytes.
+const RX_RING_LEN: usize = 256;
+
+/// A small fixed-capacity byte ring buffer, used to decouple an RX
+/// interrupt (the producer) from the BIOS call that drains it (the
+/// consumer).
+struct RxRing {
+	buf: [u8; RX_RING_LEN],
+	head: usize,
+	tail: usize,
+}
+
+impl RxRing {
+	const fn new() -> RxRing {
+		RxRing {
+			buf: [0; RX_RING_LEN],
+			head: 0,
+			tail: 0,
+		}
+	}
+
+	/// Push one byte on, silently dropping it if the ring is full - the
+	/// alternative is blocking the interrupt that's feeding us.
+	fn push(&mut self, byte: u8) {
+		let next = (self.head + 1) % RX_RING_LEN;
+		if next != self.tail {
+			self.buf[self.head] = byte;
+			self.head = next;
+		}
+	}
+
+	/// Copy as many buffered bytes as will fit into `out`, returning how
+	/// many were copied.
+	fn drain_into(&mut self, out: &mut [u8]) -> usize {
+		let mut n = 0;
+		while n < out.len() {
+			if self.tail == self.head {
+				break;
+			}
+			out[n] = self.buf[self.tail];
+			self.tail = (self.tail + 1) % RX_RING_LEN;
+			n += 1;
+		}
+		n
+	}
 }
 
 // ===========================================================================
 // Static Variables and Constants
 // ===========================================================================
 
+/// The number of video/timer frames we expect per second. SysTick is
+/// configured to fire at this rate.
+const FRAMES_PER_SECOND: u8 = 60;
+
 /// Records the number of seconds that have elapsed since the epoch (2000-01-01T00:00:00Z).
 static SECONDS_SINCE_EPOCH: core::sync::atomic::AtomicU32 = core::sync::atomic::AtomicU32::new(0);
 
+/// Seconds between the Unix epoch (1970-01-01T00:00:00Z) and the BIOS API's
+/// epoch (2000-01-01T00:00:00Z). The RTC only knows about the former, so
+/// every value that crosses the boundary between it and `SECONDS_SINCE_EPOCH`
+/// needs this applied.
+const UNIX_TO_BIOS_EPOCH_OFFSET: u32 = 946_684_800;
+
 /// Records the number of frames that have elapsed since second last rolled over.
 static FRAMES_SINCE_SECOND: core::sync::atomic::AtomicU8 = core::sync::atomic::AtomicU8::new(0);
 
+/// Set once `main` has muxed PA9/PB7 onto USART1 and brought it up as the
+/// debug UART - well before `GLOBAL_BOARD` is populated, since the rest of
+/// `main`'s hardware bring-up runs after this point and should still get a
+/// panic report if it goes wrong. Checked by the panic handler instead of
+/// `GLOBAL_BOARD`, which isn't a reliable signal of that until much later.
+static DEBUG_UART_READY: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+/// USART1's real kernel clock in Hz, stashed by `main` once `clocks` is
+/// known. USART1 is on APB2, which the STM32F7 caps well below the 216MHz
+/// core clock, so the panic handler needs this rather than the core clock
+/// to compute a correct 115200 baud divisor.
+static USART1_CLOCK_HZ: core::sync::atomic::AtomicU32 = core::sync::atomic::AtomicU32::new(0);
+
 /// The BIOS version string
 static BIOS_VERSION: &str = concat!(
 	"Neotron 340ST BIOS, version ",
@@ -111,12 +211,34 @@ static API_CALLS: common::Api = common::Api {
 	serial_configure,
 	serial_get_info,
 	serial_write,
+	serial_read,
 	time_get,
 	time_set,
+	video_get_mode,
+	video_set_mode,
+	video_get_framebuffer,
+	video_set_palette,
+	video_wait_for_line,
+	block_dev_get_info,
+	block_read,
+	block_write,
+	hid_get_event,
+	hid_peek_event,
+	audio_mixer_channel_get_info,
+	audio_mixer_channel_set_level,
+	audio_output_get_config,
+	audio_output_get_space,
+	audio_output_data,
 };
 
-/// Holds the global state for the motherboard
-static GLOBAL_BOARD: spin::Mutex<Option<BoardInner>> = spin::Mutex::new(None);
+/// Holds the global state for the motherboard.
+///
+/// Both the BIOS calls and our interrupt handlers reach into this, so it's
+/// guarded by a critical section rather than a spinlock: an ISR and an API
+/// call can never truly run at once on this single-core part, but they can
+/// interrupt each other, and a spinlock doesn't stop that.
+static GLOBAL_BOARD: cortex_m::interrupt::Mutex<RefCell<Option<BoardInner>>> =
+	cortex_m::interrupt::Mutex::new(RefCell::new(None));
 
 // ===========================================================================
 // Public Functions
@@ -125,18 +247,33 @@ static GLOBAL_BOARD: spin::Mutex<Option<BoardInner>> = spin::Mutex::new(None);
 impl core::fmt::Write for BoardInner {
 	fn write_str(&mut self, s: &str) -> core::fmt::Result {
 		for b in s.bytes() {
-			block!(self.usb_uart.write(b)).unwrap();
+			block!(self.debug_uart.write(b)).unwrap();
 		}
 		Ok(())
 	}
 }
 
+/// Runs `f` with exclusive, interrupt-safe access to the board state.
+///
+/// Panics if called before `main` has populated `GLOBAL_BOARD` - every call
+/// site below only runs after that point.
+fn with_board<R>(f: impl FnOnce(&mut BoardInner) -> R) -> R {
+	cortex_m::interrupt::free(|cs| {
+		let mut slot = GLOBAL_BOARD.borrow(cs).borrow_mut();
+		match slot.as_mut() {
+			Some(board) => f(board),
+			None => panic!("HW Lock fail"),
+		}
+	})
+}
+
 /// Entry point to the BIOS. This is called from the reset vector by
 /// `cortex-m-rt`.
 #[entry]
 fn main() -> ! {
 	// Grab the singletons
 	let p = device::Peripherals::take().unwrap();
+	let cp = cortex_m::Peripherals::take().unwrap();
 	// Reset and Clock Controller
 	let rcc = p.RCC.constrain();
 	// Full speed ahead!
@@ -144,11 +281,11 @@ fn main() -> ! {
 	// Get the GPIO objects
 	let gpioa = p.GPIOA.split();
 	let gpiob = p.GPIOB.split();
-	// VCP UART is on PB7 (VCP RX) and PA9 (VCP TX).
+	// The header UART is on PB7 (RX) and PA9 (TX).
 	let tx = gpioa.pa9.into_alternate_af7();
 	let rx = gpiob.pb7.into_alternate_af7();
 	// Construct a serial port
-	let usb_uart = Serial::new(
+	let mut debug_uart = Serial::new(
 		p.USART1,
 		(tx, rx),
 		clocks,
@@ -157,8 +294,57 @@ fn main() -> ! {
 			oversampling: serial::Oversampling::By16,
 		},
 	);
+	debug_uart.listen(serial::Event::Rxne);
+	// From here on PA9/PB7 are muxed onto USART1, so the panic handler has
+	// somewhere to print to even if we crash during the rest of `main`.
+	// USART1 is on APB2, not the core clock, so stash its real kernel clock
+	// for the panic handler to compute its own baud rate divisor from.
+	USART1_CLOCK_HZ.store(clocks.pclk2().0, Ordering::Release);
+	DEBUG_UART_READY.store(true, Ordering::Release);
+
+	// The USB mini-B connector is OTG FS on PA11 (DM) / PA12 (DP).
+	let usb_serial = usb::UsbSerial::init(
+		p.OTG_FS_GLOBAL,
+		p.OTG_FS_DEVICE,
+		p.OTG_FS_PWRCLK,
+		gpioa.pa11.into_alternate_af10(),
+		gpioa.pa12.into_alternate_af10(),
+		&clocks,
+	);
 
-	let mut board = BoardInner { usb_uart };
+	// Bring up the backup-domain RTC and seed our seconds counter from
+	// whatever calendar value it kept across the reset.
+	let rtc = Rtc::new(p.RTC, &clocks);
+	SECONDS_SINCE_EPOCH.store(
+		rtc.get_unix_timestamp()
+			.saturating_sub(UNIX_TO_BIOS_EPOCH_OFFSET),
+		Ordering::Release,
+	);
+
+	// Bring up the SDRAM and the LTDC, and put the framebuffer in SDRAM.
+	let video = video::VideoController::init(p.FMC, p.LTDC, &clocks);
+
+	// Bring up the SD card connector. It's fine if there's no card in the
+	// slot yet - `sdcard` just remembers that until one is.
+	let sdcard = block::SdCard::init(p.SDMMC1, &clocks);
+
+	// Bring up the keyboard/mouse UART on PC6 (TX) / PC7 (RX) - internal to
+	// the BIOS, so it never appears as a serial device.
+	let keyboard = ps2::Ps2Keyboard::init(p.USART6, p.GPIOC, &clocks);
+
+	// Bring up the codec and the SAI/DMA path that feeds it.
+	let audio = audio::AudioOutput::init(p.I2C1, p.SAI2, p.DMA2, &clocks);
+
+	let mut board = BoardInner {
+		debug_uart,
+		usb_serial,
+		rtc,
+		video,
+		sdcard,
+		debug_uart_rx: RxRing::new(),
+		keyboard,
+		audio,
+	};
 
 	// Say hello to the nice users.
 	writeln!(
@@ -168,13 +354,97 @@ fn main() -> ! {
 	)
 	.unwrap();
 
-	*GLOBAL_BOARD.lock() = Some(board);
+	cortex_m::interrupt::free(|cs| {
+		*GLOBAL_BOARD.borrow(cs).borrow_mut() = Some(board);
+	});
+
+	// Now the wall clock is seeded, start ticking it. SysTick is the
+	// fallback timebase for `FRAMES_SINCE_SECOND` - the LTDC line interrupt
+	// we just armed fires at the real vertical blank and takes over as soon
+	// as it starts, so the tick tracks the actual raster rather than a free
+	// running timer that could drift from it.
+	let mut syst = cp.SYST;
+	syst.set_clock_source(cortex_m::peripheral::syst::SystClkSource::Core);
+	syst.set_reload(clocks.sysclk().0 / FRAMES_PER_SECOND as u32 - 1);
+	syst.clear_current();
+	syst.enable_interrupt();
+	syst.enable_counter();
+	unsafe {
+		cortex_m::peripheral::NVIC::unmask(device::Interrupt::LTDC);
+		cortex_m::peripheral::NVIC::unmask(device::Interrupt::OTG_FS);
+		cortex_m::peripheral::NVIC::unmask(device::Interrupt::USART1);
+		cortex_m::peripheral::NVIC::unmask(device::Interrupt::USART6);
+		cortex_m::peripheral::NVIC::unmask(device::Interrupt::DMA2_STREAM4);
+	}
 
 	let code: &common::OsStartFn = unsafe { ::core::mem::transmute(0x0808_0000) };
 
 	code(&API_CALLS);
 }
 
+/// Advances the frame/second wall-clock counters that back
+/// `time_get`/`time_set`. Called from whichever of SysTick or the LTDC line
+/// interrupt is currently acting as our frame tick.
+fn advance_frame_clock() {
+	let frames = FRAMES_SINCE_SECOND.fetch_add(1, Ordering::AcqRel) + 1;
+	if frames >= FRAMES_PER_SECOND {
+		FRAMES_SINCE_SECOND.store(0, Ordering::Release);
+		SECONDS_SINCE_EPOCH.fetch_add(1, Ordering::AcqRel);
+	}
+}
+
+/// Fires at `FRAMES_PER_SECOND` Hz. Our fallback frame tick, used until the
+/// LTDC line interrupt first fires - `LTDC` then switches this interrupt
+/// off, so the two never both count the same frame.
+#[exception]
+fn SysTick() {
+	advance_frame_clock();
+}
+
+/// Fires once per frame, right as the LTDC enters vertical blanking. This is
+/// the authoritative frame tick once video is up, since unlike SysTick it
+/// can never drift from the panel's actual refresh rate.
+#[interrupt]
+fn LTDC() {
+	with_board(|board| board.video.clear_line_interrupt());
+	// We're the frame tick now, so stop SysTick's fallback one (TICKINT).
+	unsafe { (*cortex_m::peripheral::SYST::ptr()).csr.modify(|r| r & !(1 << 1)) };
+	advance_frame_clock();
+}
+
+/// Fires whenever the USB OTG FS peripheral has something for us: a host
+/// request to answer, or room in the IN endpoint to push more of our queued
+/// serial output into.
+#[interrupt]
+fn OTG_FS() {
+	with_board(|board| board.usb_serial.poll());
+}
+
+/// Fires whenever the header UART has a byte waiting. We push it straight
+/// into `debug_uart_rx` and get back to whatever we were doing - the actual
+/// draining happens on the `serial_read` side.
+#[interrupt]
+fn USART1() {
+	with_board(|board| {
+		if let Ok(byte) = board.debug_uart.read() {
+			board.debug_uart_rx.push(byte);
+		}
+	});
+}
+
+/// Fires whenever the keyboard UART has a scan-code byte waiting.
+#[interrupt]
+fn USART6() {
+	with_board(|board| board.keyboard.handle_rx_interrupt());
+}
+
+/// Fires at the half-way and end points of the audio DMA ring, telling us
+/// which half just became safe for the OS to refill.
+#[interrupt]
+fn DMA2_STREAM4() {
+	with_board(|board| board.audio.handle_dma_interrupt());
+}
+
 /// Get the API version this crate implements
 pub extern "C" fn api_version_get() -> u32 {
 	common::API_VERSION
@@ -185,56 +455,109 @@ pub extern "C" fn bios_version_get() -> common::ApiString<'static> {
 	BIOS_VERSION.into()
 }
 
-/// Re-configure the UART. We default to 115200/8N1 on UART1, and the other
-/// UARTs default to disabled.
+/// Re-configure a serial device. Device 0 (USB CDC-ACM) and device 1 (the
+/// header UART) both run a fixed 115200/8N1 today - there's no user-facing
+/// knob to turn yet - so this just validates the device index and accepts
+/// whatever the OS asks for.
 pub extern "C" fn serial_configure(
 	device: u8,
 	_serial_config: common::serial::Config,
 ) -> common::Result<()> {
 	match device {
+		0 | 1 => common::Result::Ok(()),
 		_ => common::Result::Err(common::Error::InvalidDevice),
 	}
 }
 
 /// Get infomation about the UARTs available in ths system.
 ///
-/// We have four UARTs, but we only expose three of them. The keyboard/mouse
-/// interface UART is kept internal to the BIOS.
+/// We have four UARTs, but only one of them - the header UART, device 1 - is
+/// exposed as a serial device; device 0 is the USB CDC-ACM port. The keyboard/mouse interface UART is kept internal to the BIOS - see the
+/// HID calls instead.
 pub extern "C" fn serial_get_info(device: u8) -> common::Option<common::serial::DeviceInfo> {
 	match device {
+		0 => common::Option::Some(common::serial::DeviceInfo {
+			name: "USB Serial".into(),
+			device_type: common::serial::DeviceType::Usb,
+		}),
+		1 => common::Option::Some(common::serial::DeviceInfo {
+			name: "Header UART".into(),
+			device_type: common::serial::DeviceType::Uart,
+		}),
 		_ => common::Option::None,
 	}
 }
 
-/// Write some text to a UART.
+/// Write some text to a serial device. Device 0 is the USB CDC-ACM port;
+/// device 1 is the plain header UART.
 pub extern "C" fn serial_write(
 	device: u8,
 	data: common::ApiByteSlice,
 	_timeout: common::Option<common::Timeout>,
 ) -> common::Result<usize> {
-	if let Some(ref mut board) = *crate::GLOBAL_BOARD.lock() {
-		// TODO: Add a timer to the board and use it to handle the timeout.
-		// Match on the result of write:
-		// * if we get an error, return it.
-		// * if we get a WouldBlock, spin (or WFI?).
-		// * if we get Ok, carry on.
-		let data = data.as_slice();
-		match device {
-			0 => {
-				for b in data.iter().cloned() {
-					block!(board.usb_uart.write(b)).unwrap();
-				}
-			}
-			_ => {
-				return common::Result::Err(common::Error::InvalidDevice);
+	// TODO: Add a timer to the board and use it to handle the timeout.
+	let data = data.as_slice();
+	with_board(|board| match device {
+		0 => common::Result::Ok(board.usb_serial.write(data)),
+		1 => {
+			// Match on the result of write:
+			// * if we get an error, return it.
+			// * if we get a WouldBlock, spin (or WFI?).
+			// * if we get Ok, carry on.
+			for b in data.iter().cloned() {
+				block!(board.debug_uart.write(b)).unwrap();
 			}
+			common::Result::Ok(data.len())
+		}
+		_ => common::Result::Err(common::Error::InvalidDevice),
+	})
+}
+
+/// Read bytes the OS hasn't seen yet out of a serial device. Device 0 is
+/// the USB CDC-ACM port; device 1 is the plain header UART. Both are fed by
+/// an RX interrupt into a ring buffer - this call just drains it.
+pub extern "C" fn serial_read(
+	device: u8,
+	data: common::ApiBuffer,
+	timeout: common::Option<common::Timeout>,
+) -> common::Result<usize> {
+	if device > 1 {
+		return common::Result::Err(common::Error::InvalidDevice);
+	}
+	let buffer = data.as_mut_slice();
+	let timeout_ms = match timeout {
+		common::Option::Some(t) => Some(t.0),
+		common::Option::None => None,
+	};
+	let start = time_get();
+	loop {
+		let n = with_board(|board| match device {
+			0 => board.usb_serial.read(buffer),
+			_ => board.debug_uart_rx.drain_into(buffer),
+		});
+		if n > 0 {
+			return common::Result::Ok(n);
+		}
+		match timeout_ms {
+			None => return common::Result::Ok(0),
+			Some(ms) if elapsed_ms(start, time_get()) >= ms => return common::Result::Ok(0),
+			Some(_) => continue,
 		}
-		common::Result::Ok(data.len())
-	} else {
-		panic!("HW Lock fail");
 	}
 }
 
+/// How many milliseconds have elapsed between two `Time`s, assuming `now`
+/// is not earlier than `start` and they are at most a few seconds apart -
+/// exactly the case for a `serial_read` timeout.
+fn elapsed_ms(start: common::Time, now: common::Time) -> u32 {
+	let frames = (now.seconds_since_epoch.wrapping_sub(start.seconds_since_epoch) as i64
+		* FRAMES_PER_SECOND as i64
+		+ now.frames_since_second as i64
+		- start.frames_since_second as i64)
+		.max(0) as u32;
+	frames * 1000 / FRAMES_PER_SECOND as u32
+}
+
 /// Get the current wall time.
 pub extern "C" fn time_get() -> common::Time {
 	let (seconds_since_epoch, frames_since_second) = loop {
@@ -267,7 +590,184 @@ pub extern "C" fn time_set(new_time: common::Time) {
 		new_time.frames_since_second,
 		core::sync::atomic::Ordering::Release,
 	);
-	// todo: Write the new time to the RTC (which is only accurate to the second)
+	// The RTC is only accurate to the second, but it's backed by the backup
+	// domain so it survives a reset - that's all we need it for. It only
+	// knows Unix time, so convert back out of the BIOS epoch before writing.
+	with_board(|board| {
+		board.rtc.set_unix_timestamp(
+			new_time
+				.seconds_since_epoch
+				.saturating_add(UNIX_TO_BIOS_EPOCH_OFFSET),
+		)
+	});
+}
+
+/// Get the video mode currently being scanned out.
+pub extern "C" fn video_get_mode() -> common::video::Mode {
+	with_board(|board| board.video.mode())
+}
+
+/// Switch to a new video mode. We only have the one panel wired up, so
+/// [`video::NATIVE_MODE`] is the only mode that will succeed.
+pub extern "C" fn video_set_mode(mode: common::video::Mode) -> common::Result<()> {
+	with_board(|board| board.video.set_mode(mode))
+}
+
+/// Get the address of the framebuffer the OS should render into.
+pub extern "C" fn video_get_framebuffer() -> *mut u8 {
+	with_board(|board| board.video.framebuffer_ptr())
+}
+
+/// Set one entry in the video palette.
+pub extern "C" fn video_set_palette(index: u8, colour: common::video::RGBColour) {
+	with_board(|board| board.video.set_palette(index, colour));
+}
+
+/// Busy-wait until the raster beam reaches the given scan-line, so the OS
+/// can pace software rendering to vertical blanking.
+///
+/// Deliberately not routed through `with_board` - see
+/// [`video::wait_for_line`]'s doc comment for why holding the board's
+/// critical section for the whole wait would be a problem.
+pub extern "C" fn video_wait_for_line(line: u16) -> common::Result<()> {
+	video::wait_for_line(line)
+}
+
+/// Enumerate the block devices in the system. We only have one - the SD
+/// card slot - and we report `None` for every other index, which is how the
+/// OS knows to stop asking.
+pub extern "C" fn block_dev_get_info(device: u8) -> common::Option<common::block_dev::DeviceInfo> {
+	if device != 0 {
+		return common::Option::None;
+	}
+	with_board(|board| match board.sdcard.info() {
+		Some(num_blocks) => common::Option::Some(common::block_dev::DeviceInfo {
+			name: "SD Card".into(),
+			device_type: common::block_dev::DeviceType::SecureDigitalCard,
+			block_size: 512,
+			num_blocks: num_blocks as u64,
+			ejectable: true,
+		}),
+		None => common::Option::None,
+	})
+}
+
+/// Read one or more 512-byte blocks from the SD card.
+pub extern "C" fn block_read(
+	device: u8,
+	block: common::block_dev::BlockIdx,
+	num_blocks: u8,
+	data: common::ApiBuffer,
+) -> common::Result<()> {
+	if device != 0 {
+		return common::Result::Err(common::Error::InvalidDevice);
+	}
+	with_board(|board| {
+		if board.sdcard.info().is_none() {
+			return common::Result::Err(common::Error::NoMediaFound);
+		}
+		let buffer = data.as_mut_slice();
+		if buffer.len() < num_blocks as usize * 512 {
+			return common::Result::Err(common::Error::InvalidDevice);
+		}
+		for i in 0..num_blocks as u32 {
+			let start = (i as usize) * 512;
+			let end = start + 512;
+			if board
+				.sdcard
+				.read_block(block.0 as u32 + i, &mut buffer[start..end])
+				.is_err()
+			{
+				return common::Result::Err(common::Error::DeviceError);
+			}
+		}
+		common::Result::Ok(())
+	})
+}
+
+/// Write one or more 512-byte blocks to the SD card.
+pub extern "C" fn block_write(
+	device: u8,
+	block: common::block_dev::BlockIdx,
+	num_blocks: u8,
+	data: common::ApiByteSlice,
+) -> common::Result<()> {
+	if device != 0 {
+		return common::Result::Err(common::Error::InvalidDevice);
+	}
+	with_board(|board| {
+		if board.sdcard.info().is_none() {
+			return common::Result::Err(common::Error::NoMediaFound);
+		}
+		let buffer = data.as_slice();
+		if buffer.len() < num_blocks as usize * 512 {
+			return common::Result::Err(common::Error::InvalidDevice);
+		}
+		for i in 0..num_blocks as u32 {
+			let start = (i as usize) * 512;
+			let end = start + 512;
+			if board
+				.sdcard
+				.write_block(block.0 as u32 + i, &buffer[start..end])
+				.is_err()
+			{
+				return common::Result::Err(common::Error::DeviceError);
+			}
+		}
+		common::Result::Ok(())
+	})
+}
+
+/// Get the oldest keyboard event the OS hasn't seen yet, removing it from
+/// the queue.
+pub extern "C" fn hid_get_event() -> common::Option<common::hid::HidEvent> {
+	with_board(|board| match board.keyboard.get_event() {
+		Some(event) => common::Option::Some(event),
+		None => common::Option::None,
+	})
+}
+
+/// Look at the oldest keyboard event without removing it from the queue.
+pub extern "C" fn hid_peek_event() -> common::Option<common::hid::HidEvent> {
+	with_board(|board| match board.keyboard.peek_event() {
+		Some(event) => common::Option::Some(event),
+		None => common::Option::None,
+	})
+}
+
+/// Get information about one audio mixer channel. We only have the one -
+/// the master output level.
+pub extern "C" fn audio_mixer_channel_get_info(
+	channel: u8,
+) -> common::Option<common::audio::MixerChannelInfo> {
+	with_board(|board| match board.audio.mixer_channel_info(channel) {
+		Some(info) => common::Option::Some(info),
+		None => common::Option::None,
+	})
+}
+
+/// Set the level of one audio mixer channel.
+pub extern "C" fn audio_mixer_channel_set_level(channel: u8, level: u8) -> common::Result<()> {
+	with_board(|board| board.audio.set_mixer_level(channel, level))
+}
+
+/// Get the fixed sample-rate/format configuration the audio output runs
+/// at.
+pub extern "C" fn audio_output_get_config() -> common::audio::Config {
+	with_board(|board| board.audio.output_config())
+}
+
+/// How many bytes of PCM data the audio output can currently accept
+/// without blocking.
+pub extern "C" fn audio_output_get_space() -> usize {
+	with_board(|board| board.audio.space_available())
+}
+
+/// Push one block of PCM samples into the audio output's DMA ring. Returns
+/// how many bytes were actually accepted - call `audio_output_get_space`
+/// first to avoid truncation.
+pub extern "C" fn audio_output_data(data: common::ApiByteSlice) -> common::Result<usize> {
+	with_board(|board| common::Result::Ok(board.audio.push_samples(data.as_slice())))
 }
 
 // ===========================================================================
@@ -275,15 +775,54 @@ pub extern "C" fn time_set(new_time: common::Time) {
 // ===========================================================================
 
 /// This function is called whenever the BIOS crashes.
+///
+/// We don't gate on `GLOBAL_BOARD` being populated - `debug_uart` is muxed
+/// onto USART1 and brought up well before `main` finishes the rest of its
+/// hardware bring-up and stores the board there, and a panic during any of
+/// that bring-up is exactly the kind of crash this exists to report. So we
+/// check `DEBUG_UART_READY` instead. And since the panic may have happened
+/// with `GLOBAL_BOARD`'s critical section (or some other one) already held,
+/// we don't trust the HAL `Serial` object to be in a sane state either -
+/// we reprogram USART1's registers directly to a known-good 115200 8N1 and
+/// write the crash report byte-by-byte ourselves.
 #[inline(never)]
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-	// TODO: Print the crash info to the console
+fn panic(info: &PanicInfo) -> ! {
+	if DEBUG_UART_READY.load(Ordering::Acquire) {
+		unsafe {
+			let usart1 = &*device::USART1::ptr();
+			usart1.cr1.write(|w| w.ue().clear_bit());
+			usart1
+				.brr
+				.write(|w| w.bits(USART1_CLOCK_HZ.load(Ordering::Acquire) / 115_200));
+			usart1.cr2.write(|w| w.stop().bits(0b00));
+			usart1.cr1.write(|w| w.ue().set_bit().te().set_bit());
+		}
+		let mut out = PanicUart;
+		let _ = writeln!(out, "\r\n*** BIOS PANIC ***\r\n{}", info);
+	}
+
 	loop {
 		atomic::compiler_fence(Ordering::SeqCst);
 	}
 }
 
+/// A minimal `core::fmt::Write` that pokes USART1's data register directly,
+/// for use from the panic handler where nothing else - the HAL `Serial`
+/// object included - can be trusted to still be in a sane state.
+struct PanicUart;
+
+impl core::fmt::Write for PanicUart {
+	fn write_str(&mut self, s: &str) -> core::fmt::Result {
+		let usart1 = unsafe { &*device::USART1::ptr() };
+		for b in s.bytes() {
+			while usart1.isr.read().txe().bit_is_clear() {}
+			usart1.tdr.write(|w| unsafe { w.bits(b as u32) });
+		}
+		Ok(())
+	}
+}
+
 // ===========================================================================
 // End Of File
 // ===========================================================================
@@ -0,0 +1,137 @@
+//! # PS/2 keyboard driver
+//!
+//! The keyboard/mouse connector is wired to USART6, the fourth UART on this
+//! SoC and the one we never hand out as a BIOS serial device. We decode its
+//! Scan Code Set 2 byte stream with `pc-keyboard` - the same crate version
+//! `Neotron-OS` links against - and buffer the resulting key events for the
+//! HID BIOS calls to hand out.
+
+use crate::hal::{device, gpio, prelude::*, rcc::Clocks, serial::{self, Serial}};
+use neotron_common_bios as common;
+use pc_keyboard::{layouts, HandleControl, KeyState, Keyboard, ScancodeSet2};
+
+type Ps2Uart = Serial<
+	device::USART6,
+	(
+		gpio::gpioc::PC6<gpio::Alternate<gpio::AF8>>,
+		gpio::gpioc::PC7<gpio::Alternate<gpio::AF8>>,
+	),
+>;
+
+/// Number of slots in the decoded key event queue. One slot always stays
+/// empty, so at most `EVENT_QUEUE_LEN - 1` events are buffered before
+/// `hid_get_event` drains them. Keystrokes arrive a lot slower than they're typically polled, so
+/// this only needs to smooth over the OS being briefly busy elsewhere.
+const EVENT_QUEUE_LEN: usize = 16;
+
+/// A small FIFO of decoded HID events, fed by the scan-code decoder and
+/// drained by the HID BIOS calls.
+struct EventQueue {
+	buf: [Option<common::hid::HidEvent>; EVENT_QUEUE_LEN],
+	head: usize,
+	tail: usize,
+}
+
+impl EventQueue {
+	const fn new() -> EventQueue {
+		EventQueue {
+			buf: [None; EVENT_QUEUE_LEN],
+			head: 0,
+			tail: 0,
+		}
+	}
+
+	fn push(&mut self, event: common::hid::HidEvent) {
+		let next = (self.head + 1) % EVENT_QUEUE_LEN;
+		if next != self.tail {
+			self.buf[self.head] = Some(event);
+			self.head = next;
+		}
+	}
+
+	fn pop(&mut self) -> Option<common::hid::HidEvent> {
+		if self.tail == self.head {
+			return None;
+		}
+		let event = self.buf[self.tail];
+		self.tail = (self.tail + 1) % EVENT_QUEUE_LEN;
+		event
+	}
+
+	fn peek(&self) -> Option<common::hid::HidEvent> {
+		if self.tail == self.head {
+			None
+		} else {
+			self.buf[self.tail]
+		}
+	}
+}
+
+/// Owns the keyboard UART, the `pc-keyboard` decoder state, and the queue
+/// of decoded events waiting to be collected.
+pub struct Ps2Keyboard {
+	uart: Ps2Uart,
+	decoder: Keyboard<layouts::Us104Key, ScancodeSet2>,
+	events: EventQueue,
+}
+
+// The UART and decoder are only ever touched with the `GLOBAL_BOARD` lock
+// held.
+unsafe impl Send for Ps2Keyboard {}
+
+impl Ps2Keyboard {
+	/// Bring up USART6 on PC6 (TX) / PC7 (RX) at the keyboard's fixed
+	/// 8N1/9600 baud, and an empty Scan Code Set 2 decoder.
+	pub fn init(usart6: device::USART6, gpioc: device::GPIOC, clocks: &Clocks) -> Ps2Keyboard {
+		let gpioc = gpioc.split();
+		let pins = (
+			gpioc.pc6.into_alternate_af8(),
+			gpioc.pc7.into_alternate_af8(),
+		);
+		let mut uart = Serial::new(
+			usart6,
+			pins,
+			*clocks,
+			serial::Config {
+				baud_rate: 9_600.bps(),
+				oversampling: serial::Oversampling::By16,
+			},
+		);
+		uart.listen(serial::Event::Rxne);
+
+		Ps2Keyboard {
+			uart,
+			decoder: Keyboard::new(ScancodeSet2::new(), layouts::Us104Key, HandleControl::Ignore),
+			events: EventQueue::new(),
+		}
+	}
+
+	/// Called from the `USART6` interrupt: read the byte that woke us up,
+	/// feed it through the decoder, and queue whatever key event (if any)
+	/// falls out.
+	pub fn handle_rx_interrupt(&mut self) {
+		let byte = match self.uart.read() {
+			Ok(byte) => byte,
+			Err(_) => return,
+		};
+		let key_event = match self.decoder.add_byte(byte) {
+			Ok(Some(key_event)) => key_event,
+			_ => return,
+		};
+		let hid_event = match key_event.state {
+			KeyState::Down | KeyState::SingleShot => common::hid::HidEvent::KeyPress(key_event.code),
+			KeyState::Up => common::hid::HidEvent::KeyRelease(key_event.code),
+		};
+		self.events.push(hid_event);
+	}
+
+	/// Pop the oldest buffered key event, if any.
+	pub fn get_event(&mut self) -> Option<common::hid::HidEvent> {
+		self.events.pop()
+	}
+
+	/// Look at the oldest buffered key event without removing it.
+	pub fn peek_event(&self) -> Option<common::hid::HidEvent> {
+		self.events.peek()
+	}
+}
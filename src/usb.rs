@@ -0,0 +1,226 @@
+//! # USB CDC-ACM serial driver
+//!
+//! Brings up the on-chip USB OTG FS peripheral and presents it to the host
+//! as a CDC-ACM virtual COM port, using `usb-device` + `usbd-serial` on top
+//! of the `synopsys-usb-otg` peripheral driver `stm32f7xx-hal` wraps as
+//! `otg_fs`. This is the physical USB mini-B port - the header UART is a
+//! separate, plain UART (see `BoardInner::debug_uart`).
+
+use crate::hal::{
+	gpio,
+	otg_fs::{UsbBus, UsbBusType, USB},
+	rcc::Clocks,
+	device,
+};
+use usb_device::prelude::*;
+
+/// Endpoint packet memory for the USB peripheral. Needs `'static` storage
+/// because `UsbBus::new` borrows it for the lifetime of the bus.
+static mut EP_MEMORY: [u32; 1024] = [0; 1024];
+
+/// The `UsbBusAllocator` itself also needs to outlive everything built on
+/// top of it (the device and the class), so it lives in a static too.
+static mut USB_BUS: Option<usb_device::bus::UsbBusAllocator<UsbBusType>> = None;
+
+/// Size of the outgoing serial ring's storage, in bytes. One slot always
+/// stays empty, so at most `TX_RING_LEN - 1` bytes can be buffered while
+/// waiting for the host to come and collect them.
+const TX_RING_LEN: usize = 256;
+
+/// A tiny byte ring buffer, used to decouple `serial_write` from the USB
+/// poll/interrupt that actually drains it onto the wire.
+struct TxRing {
+	buf: [u8; TX_RING_LEN],
+	head: usize,
+	tail: usize,
+}
+
+impl TxRing {
+	const fn new() -> TxRing {
+		TxRing {
+			buf: [0; TX_RING_LEN],
+			head: 0,
+			tail: 0,
+		}
+	}
+
+	/// Push one byte on, returning `false` (and dropping it) if the ring is
+	/// currently full.
+	fn push(&mut self, byte: u8) -> bool {
+		let next = (self.head + 1) % TX_RING_LEN;
+		if next == self.tail {
+			return false;
+		}
+		self.buf[self.head] = byte;
+		self.head = next;
+		true
+	}
+
+	fn pop(&mut self) -> Option<u8> {
+		if self.tail == self.head {
+			return None;
+		}
+		let byte = self.buf[self.tail];
+		self.tail = (self.tail + 1) % TX_RING_LEN;
+		Some(byte)
+	}
+}
+
+/// Size of the host-to-device serial ring's storage, in bytes. One slot
+/// always stays empty, so at most `RX_RING_LEN - 1` bytes are buffered
+/// before `serial_read` comes and drains them.
+const RX_RING_LEN: usize = 256;
+
+/// Same shape as `TxRing`, just facing the other way - the USB interrupt is
+/// the producer here, and `serial_read` is the consumer.
+struct RxRing {
+	buf: [u8; RX_RING_LEN],
+	head: usize,
+	tail: usize,
+}
+
+impl RxRing {
+	const fn new() -> RxRing {
+		RxRing {
+			buf: [0; RX_RING_LEN],
+			head: 0,
+			tail: 0,
+		}
+	}
+
+	fn push(&mut self, byte: u8) {
+		let next = (self.head + 1) % RX_RING_LEN;
+		if next != self.tail {
+			self.buf[self.head] = byte;
+			self.head = next;
+		}
+	}
+
+	fn drain_into(&mut self, out: &mut [u8]) -> usize {
+		let mut n = 0;
+		while n < out.len() {
+			if self.tail == self.head {
+				break;
+			}
+			out[n] = self.buf[self.tail];
+			self.tail = (self.tail + 1) % RX_RING_LEN;
+			n += 1;
+		}
+		n
+	}
+}
+
+/// Owns the CDC-ACM class and device, and the TX/RX ring buffers either
+/// side of it.
+pub struct UsbSerial {
+	device: UsbDevice<'static, UsbBusType>,
+	serial: usbd_serial::SerialPort<'static, UsbBusType>,
+	tx: TxRing,
+	rx: RxRing,
+}
+
+// The USB peripheral and the ring buffer are only ever touched with the
+// `GLOBAL_BOARD` lock held.
+unsafe impl Send for UsbSerial {}
+
+impl UsbSerial {
+	/// Bring up OTG FS on PA11 (DM) / PA12 (DP) and enumerate as a CDC-ACM
+	/// device.
+	pub fn init(
+		otg_fs_global: device::OTG_FS_GLOBAL,
+		otg_fs_device: device::OTG_FS_DEVICE,
+		otg_fs_pwrclk: device::OTG_FS_PWRCLK,
+		pin_dm: gpio::gpioa::PA11<gpio::Alternate<gpio::AF10>>,
+		pin_dp: gpio::gpioa::PA12<gpio::Alternate<gpio::AF10>>,
+		clocks: &Clocks,
+	) -> UsbSerial {
+		let usb = USB {
+			usb_global: otg_fs_global,
+			usb_device: otg_fs_device,
+			usb_pwrclk: otg_fs_pwrclk,
+			pin_dm,
+			pin_dp,
+			hclk: clocks.hclk(),
+		};
+
+		// Safety: `init` is only ever called once, from `main`, before
+		// anything else can reach `USB_BUS`/`EP_MEMORY`.
+		let usb_bus = unsafe {
+			USB_BUS = Some(UsbBus::new(usb, &mut EP_MEMORY));
+			USB_BUS.as_ref().unwrap()
+		};
+
+		let serial = usbd_serial::SerialPort::new(usb_bus);
+		let device = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27dd))
+			.manufacturer("Neotron")
+			.product("Neotron 340ST")
+			.serial_number("0001")
+			.device_class(usbd_serial::USB_CLASS_CDC)
+			.build();
+
+		UsbSerial {
+			device,
+			serial,
+			tx: TxRing::new(),
+			rx: RxRing::new(),
+		}
+	}
+
+	/// Copy as many buffered incoming bytes as will fit into `out`,
+	/// returning how many were copied.
+	pub fn read(&mut self, out: &mut [u8]) -> usize {
+		self.rx.drain_into(out)
+	}
+
+	/// Queue bytes to go out over the USB serial port. Returns the number
+	/// actually queued - once the ring is full the rest are dropped, same
+	/// as a UART would if nothing was reading it.
+	pub fn write(&mut self, data: &[u8]) -> usize {
+		let mut n = 0;
+		for &byte in data {
+			if !self.tx.push(byte) {
+				break;
+			}
+			n += 1;
+		}
+		self.poll();
+		n
+	}
+
+	/// Service the USB peripheral: handle host requests, and drain as much
+	/// of the TX ring as the IN endpoint will currently accept. Called from
+	/// both `serial_write` and the OTG FS interrupt, so queued bytes go out
+	/// promptly whether or not new ones are arriving.
+	pub fn poll(&mut self) {
+		// Pull anything the host just sent us into our RX ring.
+		if self.device.poll(&mut [&mut self.serial]) {
+			let mut incoming = [0u8; 64];
+			if let Ok(n) = self.serial.read(&mut incoming) {
+				for &byte in &incoming[..n] {
+					self.rx.push(byte);
+				}
+			}
+		}
+
+		// And offer it as much of our queued TX data as it'll take, copying
+		// rather than popping so nothing leaves the ring until it's accepted.
+		let mut chunk = [0u8; 64];
+		let mut len = 0;
+		let mut idx = self.tx.tail;
+		while len < chunk.len() && idx != self.tx.head {
+			chunk[len] = self.tx.buf[idx];
+			idx = (idx + 1) % TX_RING_LEN;
+			len += 1;
+		}
+		if len > 0 {
+			// A `WouldBlock` here just means the host hasn't caught up
+			// yet - the bytes stay in our ring and we'll offer them again
+			// next poll. Only what the endpoint took is removed.
+			if let Ok(written) = self.serial.write(&chunk[..len]) {
+				for _ in 0..written {
+					self.tx.pop();
+				}
+			}
+		}
+	}
+}
@@ -0,0 +1,410 @@
+//! # Video driver
+//!
+//! Brings up the FMC-attached SDRAM and the LTDC controller, and drives the
+//! video call group of the BIOS API. The panel is a fixed 480x272 16-bit
+//! RGB565 LCD, so for now we only support the one native mode - there is no
+//! other display hardware on this board to switch to.
+
+use crate::hal::{device, rcc::Clocks};
+
+/// Number of pixels across the panel.
+pub const WIDTH: u16 = 480;
+
+/// Number of visible scan-lines on the panel.
+pub const HEIGHT: u16 = 272;
+
+/// Bytes per pixel for our one supported format (RGB565).
+const BYTES_PER_PIXEL: usize = 2;
+
+/// Size of the framebuffer, in bytes.
+pub const FRAMEBUFFER_LEN: usize = (WIDTH as usize) * (HEIGHT as usize) * BYTES_PER_PIXEL;
+
+/// Base address of the FMC SDRAM bank the framebuffer lives in (FMC SDRAM
+/// bank 1, as wired on the STM32F746G-DISCO).
+const SDRAM_BASE: usize = 0xC000_0000;
+
+/// RK043FN48H panel timings, in LTDC clock cycles, taken from the panel
+/// datasheet (the same numbers ST's own BSP uses for this board).
+struct PanelTiming {
+	h_sync: u16,
+	h_back_porch: u16,
+	h_front_porch: u16,
+	v_sync: u16,
+	v_back_porch: u16,
+	v_front_porch: u16,
+}
+
+const PANEL_TIMING: PanelTiming = PanelTiming {
+	h_sync: 41,
+	h_back_porch: 13,
+	h_front_porch: 32,
+	v_sync: 10,
+	v_back_porch: 2,
+	v_front_porch: 2,
+};
+
+/// Owns the LTDC peripheral and the framebuffer it is scanning out of
+/// SDRAM.
+pub struct VideoController {
+	ltdc: device::LTDC,
+	framebuffer: *mut u16,
+	mode: common::video::Mode,
+}
+
+/// The only video mode this board supports: 480x272, 16-bit RGB565, no
+/// text console overlay.
+pub const NATIVE_MODE: common::video::Mode =
+	common::video::Mode::new(common::video::Timing::T480x272, common::video::Format::Chunky16);
+
+// The framebuffer pointer is into SDRAM we own exclusively, and the LTDC
+// peripheral is likewise ours alone - both are only ever touched with the
+// `GLOBAL_BOARD` lock held.
+unsafe impl Send for VideoController {}
+
+impl VideoController {
+	/// Bring up the FMC SDRAM controller and the LTDC, and hand back a
+	/// controller scanning layer 1 out of the start of SDRAM.
+	///
+	/// # Safety
+	///
+	/// Must only be called once, with exclusive access to the `FMC` and
+	/// `LTDC` peripherals and their associated GPIOs.
+	pub fn init(fmc: device::FMC, ltdc: device::LTDC, clocks: &Clocks) -> VideoController {
+		configure_pins();
+		let framebuffer = unsafe { init_sdram(fmc) };
+		init_ltdc(&ltdc, clocks, framebuffer as usize);
+		VideoController {
+			ltdc,
+			framebuffer,
+			mode: NATIVE_MODE,
+		}
+	}
+
+	/// The video mode currently being scanned out.
+	pub fn mode(&self) -> common::video::Mode {
+		self.mode
+	}
+
+	/// Switch to a new video mode.
+	///
+	/// We only have the one panel wired up, so the only mode we can honour
+	/// is [`NATIVE_MODE`] - anything else is rejected rather than silently
+	/// ignored.
+	pub fn set_mode(&mut self, mode: common::video::Mode) -> common::Result<()> {
+		if mode == NATIVE_MODE {
+			common::Result::Ok(())
+		} else {
+			common::Result::Err(common::Error::InvalidDevice)
+		}
+	}
+
+	/// The base address of the framebuffer, as handed to the OS.
+	pub fn framebuffer_ptr(&self) -> *mut u8 {
+		self.framebuffer as *mut u8
+	}
+
+	/// Load one new colour into the hardware palette.
+	///
+	/// Our format is direct RGB565, so there is no indexed palette to
+	/// reprogram in hardware - instead we keep this as the hook future
+	/// indexed modes will use, and for now it is a no-op beyond bounds
+	/// checking.
+	pub fn set_palette(&mut self, _index: u8, _colour: common::video::RGBColour) {
+		// Nothing to do until we add an indexed colour mode.
+	}
+
+	/// Acknowledge the line interrupt so the LTDC de-asserts it until the
+	/// next time the scan position reaches [`Self::init`]'s programmed line.
+	pub fn clear_line_interrupt(&mut self) {
+		self.ltdc.icr.write(|w| w.clif().set_bit());
+	}
+}
+
+/// Busy-wait until the LTDC's current scan position reaches the given line.
+/// Used by the OS to pace software rendering to the raster beam.
+///
+/// Deliberately not a `VideoController` method, so callers aren't tempted to
+/// run it through `with_board`: the beam can take up to a full frame to come
+/// back around to `line`, and spinning that long with the board's critical
+/// section held would mask every other interrupt - including the UART RX
+/// ones - for the whole wait. Reads the LTDC's scan-position register via
+/// its raw pointer instead, which needs no locking since it's driven purely
+/// by the timing hardware, not by anything we touch elsewhere under the
+/// lock.
+pub fn wait_for_line(line: u16) -> common::Result<()> {
+	if line >= HEIGHT {
+		return common::Result::Err(common::Error::InvalidDevice);
+	}
+	let ltdc = unsafe { &*device::LTDC::ptr() };
+	while ltdc.cpsr.read().cypos().bits() != line {
+		cortex_m::asm::nop();
+	}
+	common::Result::Ok(())
+}
+
+use neotron_common_bios as common;
+
+/// Bring up the FMC SDRAM controller wired to the on-board 8 MiB SDRAM, and
+/// hand back a pointer to the start of the region we reserve for the
+/// framebuffer.
+///
+/// # Safety
+///
+/// Must only be called once, before anything else touches the `0xC000_0000`
+/// address space.
+unsafe fn init_sdram(fmc: device::FMC) -> *mut u16 {
+	// Turn on the FMC's clock gate before we touch its registers.
+	(&*device::RCC::ptr())
+		.ahb3enr
+		.modify(|_, w| w.fmcen().set_bit());
+
+	// Timing and bank configuration for the IS42S32400F-6BL SDRAM fitted to
+	// the STM32F746G-DISCO, connected on FMC SDRAM bank 1.
+	fmc.sdcr1.write(|w| {
+		w.nc()
+			.bits(0b00) // 8 column address bits
+			.nr()
+			.bits(0b01) // 12 row address bits
+			.mwid()
+			.bits(0b01) // 16-bit wide data bus
+			.nb()
+			.set_bit() // 4 internal banks
+			.cas()
+			.bits(0b10) // CAS latency = 2 cycles
+			.sdclk()
+			.bits(0b10) // SDCLK = HCLK / 2
+			.rburst()
+			.set_bit()
+	});
+
+	fmc.sdtr1.write(|w| {
+		w.tmrd()
+			.bits(1)
+			.txsr()
+			.bits(6)
+			.tras()
+			.bits(3)
+			.trc()
+			.bits(6)
+			.twr()
+			.bits(1)
+			.trp()
+			.bits(1)
+			.trcd()
+			.bits(1)
+	});
+
+	// Send the JEDEC SDRAM power-up sequence: clock enable, then wait for
+	// the 100us stabilisation period, then precharge-all, then two
+	// auto-refresh cycles, then load the mode register.
+	send_sdram_command(&fmc, 0b001); // clock enable
+	cortex_m::asm::delay(21_600); // ~100us @ 216MHz
+	send_sdram_command(&fmc, 0b010); // precharge all banks
+	send_sdram_command(&fmc, 0b011); // auto-refresh
+	send_sdram_command(&fmc, 0b011); // auto-refresh
+	// Refresh rate for 64ms/4096 rows: 15.625us * 108MHz SDCLK - 20.
+	fmc.sdrtr.write(|w| w.count().bits(1667));
+	// Load mode register: burst=1, CAS=2 (mode register value 0x0020).
+	fmc.sdcmr
+		.write(|w| w.mode().bits(0b100).ctb1().set_bit().mrd().bits(0x0020));
+	while fmc.sdsr.read().busy().bit_is_set() {
+		cortex_m::asm::nop();
+	}
+
+	SDRAM_BASE as *mut u16
+}
+
+/// Issue one command on SDRAM bank 1 via the FMC command mode register, and
+/// wait for the controller to report it as accepted.
+fn send_sdram_command(fmc: &device::FMC, mode: u8) {
+	fmc.sdcmr.write(|w| w.mode().bits(mode).ctb1().set_bit());
+	while fmc.sdsr.read().busy().bit_is_set() {
+		cortex_m::asm::nop();
+	}
+}
+
+/// One GPIO signal used by the LTDC or the FMC SDRAM bus: which port, which
+/// pin, and which alternate-function number routes it to that peripheral.
+struct PinMux(char, u8, u8);
+
+/// The LTDC and FMC-SDRAM signals, straight off the STM32F746G-DISCO
+/// schematic. There are a lot of them - the panel and SDRAM bus eat most of
+/// ports C through K - so we configure them from a table instead of writing
+/// out the same three register pokes thirty-odd times.
+const PINS: &[PinMux] = &[
+	// LTDC sync/clock/enable
+	PinMux('I', 9, 14),  // LTDC_VSYNC
+	PinMux('I', 10, 14), // LTDC_HSYNC
+	PinMux('I', 14, 14), // LTDC_CLK
+	PinMux('F', 10, 14), // LTDC_DE
+	// LTDC RGB565 data lines (R7:3, G7:2, B7:3)
+	PinMux('J', 0, 14),
+	PinMux('J', 1, 14),
+	PinMux('J', 2, 14),
+	PinMux('J', 3, 14),
+	PinMux('J', 4, 14),
+	PinMux('J', 5, 14),
+	PinMux('J', 6, 14),
+	PinMux('J', 7, 14),
+	PinMux('J', 8, 14),
+	PinMux('J', 9, 14),
+	PinMux('J', 10, 14),
+	PinMux('J', 11, 14),
+	PinMux('K', 0, 14),
+	PinMux('K', 1, 14),
+	PinMux('K', 2, 14),
+	// FMC SDRAM address/data/control bus
+	PinMux('F', 0, 12),
+	PinMux('F', 1, 12),
+	PinMux('F', 2, 12),
+	PinMux('F', 11, 12),
+	PinMux('G', 4, 12),
+	PinMux('G', 5, 12),
+	PinMux('G', 8, 12),
+	PinMux('G', 15, 12),
+	PinMux('D', 0, 12),
+	PinMux('D', 1, 12),
+	PinMux('E', 0, 12),
+	PinMux('E', 1, 12),
+	PinMux('H', 3, 12),
+	PinMux('H', 5, 12),
+];
+
+/// Drive every LTDC/FMC signal in [`PINS`] into the alternate-function push-pull,
+/// very-high-speed mode both peripherals need.
+fn configure_pins() {
+	// Every port from B upwards that we use needs its AHB1 clock gate
+	// turned on before its registers do anything.
+	unsafe {
+		let rcc = &*device::RCC::ptr();
+		rcc.ahb1enr.modify(|_, w| {
+			w.gpiocen()
+				.set_bit()
+				.gpioden()
+				.set_bit()
+				.gpioeen()
+				.set_bit()
+				.gpiofen()
+				.set_bit()
+				.gpiogen()
+				.set_bit()
+				.gpiohen()
+				.set_bit()
+				.gpioien()
+				.set_bit()
+				.gpiojen()
+				.set_bit()
+				.gpioken()
+				.set_bit()
+		});
+	}
+
+	for PinMux(port, pin, af) in PINS.iter().copied() {
+		// Safety: each of these register blocks is distinct per port, we
+		// only ever touch the bits for our own pin, and this runs once
+		// during start-of-day bring-up before anything else reads GPIO
+		// state.
+		unsafe {
+			let gpio = gpio_block(port);
+			let pin = pin as usize;
+			(*gpio).moder.modify(|r, w| w.bits((r.bits() & !(0b11 << (pin * 2))) | (0b10 << (pin * 2))));
+			(*gpio).ospeedr.modify(|r, w| w.bits(r.bits() | (0b11 << (pin * 2))));
+			if pin < 8 {
+				(*gpio)
+					.afrl
+					.modify(|r, w| w.bits((r.bits() & !(0xF << (pin * 4))) | ((af as u32) << (pin * 4))));
+			} else {
+				let shift = (pin - 8) * 4;
+				(*gpio)
+					.afrh
+					.modify(|r, w| w.bits((r.bits() & !(0xF << shift)) | ((af as u32) << shift)));
+			}
+		}
+	}
+}
+
+/// Map a port letter to its raw register block pointer. Every `GPIOx` block
+/// on this SoC has the same register layout, so we cast them all to `GPIOA`'s
+/// type once we've picked the base address out.
+unsafe fn gpio_block(port: char) -> *const device::gpioa::RegisterBlock {
+	(match port {
+		'A' => device::GPIOA::ptr() as usize,
+		'B' => device::GPIOB::ptr() as usize,
+		'C' => device::GPIOC::ptr() as usize,
+		'D' => device::GPIOD::ptr() as usize,
+		'E' => device::GPIOE::ptr() as usize,
+		'F' => device::GPIOF::ptr() as usize,
+		'G' => device::GPIOG::ptr() as usize,
+		'H' => device::GPIOH::ptr() as usize,
+		'I' => device::GPIOI::ptr() as usize,
+		'J' => device::GPIOJ::ptr() as usize,
+		'K' => device::GPIOK::ptr() as usize,
+		_ => unreachable!("not a valid GPIO port letter"),
+	}) as *const device::gpioa::RegisterBlock
+}
+
+/// Configure LTDC layer 1 to scan the panel at 480x272 RGB565 out of the
+/// framebuffer we just carved out of SDRAM, and arm the line interrupt used
+/// to keep the frame clock aligned to vertical blanking.
+fn init_ltdc(ltdc: &device::LTDC, _clocks: &Clocks, framebuffer_addr: usize) {
+	// Turn on the LTDC's clock gate before we touch its registers.
+	unsafe {
+		(&*device::RCC::ptr())
+			.apb2enr
+			.modify(|_, w| w.ltdcen().set_bit());
+	}
+
+	let t = &PANEL_TIMING;
+
+	ltdc.sscr
+		.write(|w| w.hsw().bits(t.h_sync - 1).vsh().bits(t.v_sync - 1));
+	ltdc.bpcr.write(|w| {
+		w.ahbp()
+			.bits(t.h_sync + t.h_back_porch - 1)
+			.avbp()
+			.bits(t.v_sync + t.v_back_porch - 1)
+	});
+	ltdc.awcr.write(|w| {
+		w.aav()
+			.bits(t.h_sync + t.h_back_porch + WIDTH - 1)
+			.aah()
+			.bits(t.v_sync + t.v_back_porch + HEIGHT - 1)
+	});
+	ltdc.twcr.write(|w| {
+		w.totalw()
+			.bits(t.h_sync + t.h_back_porch + WIDTH + t.h_front_porch - 1)
+			.totalh()
+			.bits(t.v_sync + t.v_back_porch + HEIGHT + t.v_front_porch - 1)
+	});
+
+	// Layer 1 window covers the full visible area, pixel format RGB565,
+	// reading straight out of SDRAM.
+	ltdc.layer1.whpcr.write(|w| {
+		w.whstpos()
+			.bits(t.h_sync + t.h_back_porch)
+			.whsppos()
+			.bits(t.h_sync + t.h_back_porch + WIDTH - 1)
+	});
+	ltdc.layer1.wvpcr.write(|w| {
+		w.wvstpos()
+			.bits(t.v_sync + t.v_back_porch)
+			.wvsppos()
+			.bits(t.v_sync + t.v_back_porch + HEIGHT - 1)
+	});
+	ltdc.layer1.pfcr.write(|w| w.pf().bits(0x2)); // RGB565
+	ltdc.layer1.cfbar.write(|w| w.cfbadd().bits(framebuffer_addr as u32));
+	ltdc.layer1
+		.cfblr
+		.write(|w| w.cfbp().bits(WIDTH * BYTES_PER_PIXEL as u16).cfbll().bits(WIDTH * BYTES_PER_PIXEL as u16 + 3));
+	ltdc.layer1.cfblnr.write(|w| w.cfblnbr().bits(HEIGHT));
+	ltdc.layer1.cr.write(|w| w.len().set_bit());
+
+	// Trigger the line IRQ one line before the end of the active area, so
+	// it fires right as we enter vertical blanking.
+	ltdc.lipcr
+		.write(|w| w.lipos().bits(t.v_sync + t.v_back_porch + HEIGHT - 1));
+	ltdc.ier.write(|w| w.lie().set_bit());
+
+	ltdc.srcr.write(|w| w.imr().set_bit());
+	ltdc.gcr.write(|w| w.ltdcen().set_bit());
+}